@@ -0,0 +1,168 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use ethers::types::{Address, H256, U256, U64};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time;
+
+/// 一次检测到的池子余额变化。以前 trace_state_diff 里是直接拼一条 info! 日志字符串，
+/// 现在先组装成结构化的 SwapRecord，再交给 EventSink 去落地——方便查询、聚合，
+/// 也不用每加一个输出渠道就重新拼一遍日志格式
+#[derive(Debug, Clone, Serialize)]
+pub struct SwapRecord {
+    pub tx_hash: H256,
+    pub block_number: U64,
+    pub pool_address: Address,
+    pub token_in: Address,
+    pub token_out: Address,
+    pub balance_from: U256,
+    pub balance_to: U256,
+    pub detected_at: DateTime<Utc>,
+    // 只有 UniswapV3 池子才有：从池子自己的 slot0/liquidity storage diff 里读出来的
+    // 价格和流动性信息，UniswapV2 池子没有这些字段，统一用 None 兜底
+    pub sqrt_price_x96: Option<U256>,
+    pub tick: Option<i32>,
+    pub liquidity: Option<U256>,
+}
+
+/// 检测到的 SwapRecord 往哪里送是可插拔的，trace_state_diff 只管探测、不用关心落地到哪
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn emit(&self, record: SwapRecord);
+}
+
+/// 保留改造前的行为：原样 info! 到标准输出
+pub struct StdoutSink;
+
+#[async_trait]
+impl EventSink for StdoutSink {
+    async fn emit(&self, record: SwapRecord) {
+        log::info!(
+            "(Tx #{}) Balance change: {} -> {} @ Pool {}",
+            record.tx_hash,
+            record.balance_from,
+            record.balance_to,
+            record.pool_address
+        );
+    }
+}
+
+/// 把 SwapRecord 攒成批，按 newline-delimited JSON 发给一个 HTTP 接口，
+/// 格式上和 ZincObserve 这类兼容 Elasticsearch 批量写入协议的可观测性后端对得上。
+/// URL/账号密码从环境变量读取：
+/// - `SINK_HTTP_ENDPOINT`：批量写入接口地址
+/// - `SINK_HTTP_USERNAME` / `SINK_HTTP_PASSWORD`：HTTP Basic Auth，可选
+#[derive(Clone)]
+pub struct HttpJsonSinkConfig {
+    pub endpoint: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub flush_interval: Duration,
+    pub flush_size: usize,
+}
+
+impl HttpJsonSinkConfig {
+    pub fn from_env() -> Option<Self> {
+        let endpoint = std::env::var("SINK_HTTP_ENDPOINT").ok()?;
+        Some(Self {
+            endpoint,
+            username: std::env::var("SINK_HTTP_USERNAME").ok(),
+            password: std::env::var("SINK_HTTP_PASSWORD").ok(),
+            flush_interval: Duration::from_secs(5),
+            flush_size: 50,
+        })
+    }
+}
+
+/// 缓冲 + 批量发送，热路径（探测逻辑）只往内存里追加一条记录，真正的网络 IO
+/// 要么等凑够 flush_size，要么等后台定时器到点，都不会卡住调用 emit 的那次探测。
+pub struct HttpJsonSink {
+    config: HttpJsonSinkConfig,
+    buffer: Mutex<Vec<SwapRecord>>,
+}
+
+impl HttpJsonSink {
+    pub fn new(config: HttpJsonSinkConfig) -> Arc<Self> {
+        let sink = Arc::new(Self {
+            config,
+            buffer: Mutex::new(Vec::new()),
+        });
+        // 后台定时器：就算一直凑不够 flush_size，记录也不会一直憋在内存里
+        let flusher = sink.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(flusher.config.flush_interval);
+            loop {
+                interval.tick().await;
+                flusher.flush().await;
+            }
+        });
+        sink
+    }
+
+    async fn flush(&self) {
+        let records = {
+            let mut buffer = self.buffer.lock().await;
+            if buffer.is_empty() {
+                return;
+            }
+            std::mem::take(&mut *buffer)
+        };
+        let count = records.len();
+        if let Err(err) = Self::post_batch(&self.config, &records).await {
+            log::warn!(
+                "failed to flush {} swap records to {}: {}",
+                count,
+                self.config.endpoint,
+                err
+            );
+        }
+    }
+
+    async fn post_batch(config: &HttpJsonSinkConfig, records: &[SwapRecord]) -> Result<()> {
+        // ndjson：每条记录一行，是 Elasticsearch/ZincObserve 批量写入接口的标准格式
+        let mut body = String::new();
+        for record in records {
+            body.push_str(&serde_json::to_string(record)?);
+            body.push('\n');
+        }
+        let client = reqwest::Client::new();
+        let mut request = client
+            .post(&config.endpoint)
+            .header("Content-Type", "application/x-ndjson")
+            .body(body);
+        if let Some(username) = &config.username {
+            request = request.basic_auth(username, config.password.clone());
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventSink for HttpJsonSink {
+    async fn emit(&self, record: SwapRecord) {
+        let batch = {
+            let mut buffer = self.buffer.lock().await;
+            buffer.push(record);
+            if buffer.len() < self.config.flush_size {
+                return;
+            }
+            std::mem::take(&mut *buffer)
+        };
+        // 凑够 flush_size 时把这一批交给后台任务发送，emit 本身不等网络 IO
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            if let Err(err) = Self::post_batch(&config, &batch).await {
+                log::warn!(
+                    "failed to flush {} swap records to {}: {}",
+                    batch.len(),
+                    config.endpoint,
+                    err
+                );
+            }
+        });
+    }
+}
@@ -0,0 +1,7 @@
+pub mod events;
+pub mod logger;
+pub mod mempool;
+pub mod simulation;
+pub mod slots;
+pub mod utils;
+pub mod watching;
@@ -0,0 +1,180 @@
+use dashmap::DashMap;
+use ethers::types::{Address, Transaction, U256};
+use std::collections::BTreeMap;
+
+use crate::utils::effective_gas_price;
+
+// geth 的 txpool 默认也是 10%，新交易要比旧交易的有效单价高出这个百分比才允许替换同一个 (sender, nonce)
+pub const DEFAULT_PRICE_BUMP_PERCENT: u64 = 10;
+
+/// 参照 geth 风格的 tx pool 建模：每个账户的交易按 nonce 分桶，
+/// nonce 和账户"下一个可执行 nonce"连续的放进 `pending`（可以立刻模拟执行），
+/// 有 nonce 空洞、还排不上号的放进 `queued`。
+///
+/// 之前 pending-tx 任务是直接把每笔收到的 Transaction 丢给 trace_state_diff，
+/// 被替换/重组/nonce 跳号的交易都会被当成新交易重复处理；这里维护这部分状态，
+/// 只把"新晋可执行、没有被更高价交易取代"的交易放出去下游模拟。
+pub struct Mempool {
+    pending: DashMap<Address, BTreeMap<u64, Transaction>>,
+    queued: DashMap<Address, BTreeMap<u64, Transaction>>,
+    // 链上已确认的边界：小于这个 nonce 的交易肯定已经上链了，第一次见到某个地址时
+    // 用 get_transaction_count 兜底，之后只会被 prune_confirmed 往前推
+    floor_nonce: DashMap<Address, u64>,
+    // pending 里已经连续占住的 nonce 范围是 [floor_nonce, pending_tip)，这是促进游标：
+    // 新交易 nonce == pending_tip 才会把 pending 边界往前推一格，< pending_tip 则是在
+    // 替换一笔已经连续、可执行的交易（之前这两个概念共用 next_nonce，导致后者的场景
+    // 一进来就先被当成“旧交易”丢弃，price bump 替换逻辑根本走不到）
+    pending_tip: DashMap<Address, u64>,
+    price_bump_percent: u64,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(DEFAULT_PRICE_BUMP_PERCENT)
+    }
+}
+
+impl Mempool {
+    pub fn new(price_bump_percent: u64) -> Self {
+        Self {
+            pending: DashMap::new(),
+            queued: DashMap::new(),
+            floor_nonce: DashMap::new(),
+            pending_tip: DashMap::new(),
+            price_bump_percent,
+        }
+    }
+
+    /// 第一次见到某个地址时调用，用链上查到的 nonce 给它的确认边界和促进游标兜底
+    pub fn seed_account(&self, sender: Address, on_chain_nonce: u64) {
+        self.floor_nonce.entry(sender).or_insert(on_chain_nonce);
+        self.pending_tip.entry(sender).or_insert(on_chain_nonce);
+    }
+
+    pub fn is_known_account(&self, sender: Address) -> bool {
+        self.floor_nonce.contains_key(&sender)
+    }
+
+    /// 插入一笔新收到的 pending 交易，返回因为这次插入而新晋/重新可执行、需要下游模拟的交易：
+    /// 可能是这笔交易本身（填上了 pending 的新位置，或者替换了原来占着这个 nonce 的交易），
+    /// 也可能还带上它填平 nonce 空洞后一并放出来的后续交易。
+    /// 调用前必须先用 `seed_account` 给这个地址设好确认边界。
+    pub fn insert(&self, tx: Transaction, base_fee: U256) -> Vec<Transaction> {
+        let sender = tx.from;
+        let nonce = tx.nonce.as_u64();
+        let floor = self.floor_nonce.get(&sender).map(|n| *n).unwrap_or(nonce);
+
+        if nonce < floor {
+            // 链上 nonce 已经超过这笔交易，肯定是旧交易了，丢弃
+            return Vec::new();
+        }
+
+        let tip = self.pending_tip.get(&sender).map(|n| *n).unwrap_or(floor);
+
+        if nonce > tip {
+            // nonce 空洞，先放进 queued，等前面的 nonce 补上了再放出来
+            self.try_replace_or_insert(&self.queued, sender, nonce, tx, base_fee);
+            return Vec::new();
+        }
+
+        // nonce <= tip：要么正好填上当前促进游标（pending 边界往前推一格），
+        // 要么是在替换一笔已经躺在 pending 里、本来就可执行的交易——两种情况都要走
+        // price bump 判断，赢的那笔都要下发给下游重新模拟
+        if !self.try_replace_or_insert(&self.pending, sender, nonce, tx.clone(), base_fee) {
+            return Vec::new();
+        }
+        if nonce < tip {
+            return vec![tx];
+        }
+        self.pending_tip.insert(sender, tip + 1);
+        let mut promoted = vec![tx];
+        promoted.extend(self.promote_from_queued(sender));
+        promoted
+    }
+
+    /// 新区块到来后，用账户链上最新 nonce 清掉已经确认上链的交易；
+    /// 如果链上 nonce 往前跳过了我们追踪的促进游标，顺便把 queued 里接得上的交易促进到 pending。
+    pub fn prune_confirmed(&self, sender: Address, on_chain_nonce: u64) -> Vec<Transaction> {
+        if let Some(mut pending) = self.pending.get_mut(&sender) {
+            pending.retain(|&nonce, _| nonce >= on_chain_nonce);
+        }
+        if let Some(mut queued) = self.queued.get_mut(&sender) {
+            queued.retain(|&nonce, _| nonce >= on_chain_nonce);
+        }
+        let current_floor = self.floor_nonce.get(&sender).map(|n| *n).unwrap_or(0);
+        if on_chain_nonce > current_floor {
+            self.floor_nonce.insert(sender, on_chain_nonce);
+        }
+        let current_tip = self
+            .pending_tip
+            .get(&sender)
+            .map(|n| *n)
+            .unwrap_or(current_floor);
+        if on_chain_nonce > current_tip {
+            self.pending_tip.insert(sender, on_chain_nonce);
+        }
+        self.promote_from_queued(sender)
+    }
+
+    /// 列出当前还在跟踪的所有地址，每个区块用它来决定要去链上刷新哪些账户的 nonce
+    pub fn tracked_senders(&self) -> Vec<Address> {
+        self.floor_nonce.iter().map(|entry| *entry.key()).collect()
+    }
+
+    // 同一个 (sender, nonce) 已经有交易了，只有新交易的有效单价比旧交易至少高
+    // price_bump_percent% 才允许替换，否则丢弃新交易，这和 geth 的 price bump 规则一致
+    fn try_replace_or_insert(
+        &self,
+        bucket: &DashMap<Address, BTreeMap<u64, Transaction>>,
+        sender: Address,
+        nonce: u64,
+        tx: Transaction,
+        base_fee: U256,
+    ) -> bool {
+        let mut entries = bucket.entry(sender).or_default();
+        match entries.get(&nonce) {
+            Some(existing) => {
+                let old_price = effective_gas_price(existing, base_fee).unwrap_or_default();
+                let new_price = effective_gas_price(&tx, base_fee).unwrap_or_default();
+                let min_required =
+                    old_price + old_price * U256::from(self.price_bump_percent) / U256::from(100u64);
+                if new_price < min_required {
+                    return false;
+                }
+                entries.insert(nonce, tx);
+                true
+            }
+            None => {
+                entries.insert(nonce, tx);
+                true
+            }
+        }
+    }
+
+    // 从 pending_tip 开始，把 queued 里连续的交易一个个挪到 pending，直到出现空洞为止
+    fn promote_from_queued(&self, sender: Address) -> Vec<Transaction> {
+        let mut promoted = Vec::new();
+        loop {
+            let tip = match self.pending_tip.get(&sender) {
+                Some(n) => *n,
+                None => break,
+            };
+            let moved = match self.queued.get_mut(&sender) {
+                Some(mut q) => q.remove(&tip),
+                None => None,
+            };
+            match moved {
+                Some(tx) => {
+                    self.pending
+                        .entry(sender)
+                        .or_default()
+                        .insert(tip, tx.clone());
+                    promoted.push(tx);
+                    self.pending_tip.insert(sender, tip + 1);
+                }
+                None => break,
+            }
+        }
+        promoted
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::Result;
+use ethers::{
+    providers::{Middleware, Provider, Ws},
+    types::{Address, Transaction, H256, U256, U64},
+};
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{
+        AccountInfo, Address as RevmAddress, Bytecode, ExecutionResult, ResultAndState,
+        TransactTo, B256, U256 as RevmU256,
+    },
+    Evm,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+
+use crate::watching::NewBlock;
+
+// revm 的地址/哈希类型和 ethers 的地址/哈希类型内存布局一致，但是是两个不同的 crate，
+// 所以来回转换的时候要显式地转一下类型，不能直接 .into()
+fn to_revm_address(address: Address) -> RevmAddress {
+    RevmAddress::from(address.0)
+}
+fn to_ethers_address(address: RevmAddress) -> Address {
+    Address::from(address.into_array())
+}
+fn to_ethers_h256(value: RevmU256) -> H256 {
+    H256::from(value.to_be_bytes())
+}
+
+/// 某个存储槽在模拟执行前后的值
+#[derive(Debug, Clone)]
+pub struct StorageSlotDiff {
+    pub from: H256,
+    pub to: H256,
+}
+
+/// 单个账户在这笔交易里被改动过的存储槽
+#[derive(Debug, Clone, Default)]
+pub struct AccountDiff {
+    pub storage: HashMap<H256, StorageSlotDiff>,
+}
+
+// 保持和旧的 provider.trace_call(.., TraceType::StateDiff, ..) 返回的形状一致，
+// 这样 watching.rs 里原有的“池子交集 + 余额槽位”判断逻辑可以原样复用
+pub type StateDiffMap = HashMap<Address, AccountDiff>;
+
+/// 从 `Provider<Ws>` 惰性拉取账户/存储/字节码的只读数据源。
+/// 实现的是 `DatabaseRef`（而不是 `Database`），所以可以被多笔交易的模拟共享，
+/// 不需要互斥可变借用；真正的缓存由外层的 `CacheDB` 负责。
+pub struct ForkDb {
+    provider: Arc<Provider<Ws>>,
+    block_number: U64,
+}
+
+impl ForkDb {
+    pub fn new(provider: Arc<Provider<Ws>>, block_number: U64) -> Self {
+        Self {
+            provider,
+            block_number,
+        }
+    }
+}
+
+impl Clone for ForkDb {
+    fn clone(&self) -> Self {
+        Self {
+            provider: self.provider.clone(),
+            block_number: self.block_number,
+        }
+    }
+}
+
+impl DatabaseRef for ForkDb {
+    type Error = anyhow::Error;
+
+    // 账户的 balance/nonce/code，在 fork 的那个区块高度下去链上查
+    fn basic_ref(&self, address: RevmAddress) -> Result<Option<AccountInfo>, Self::Error> {
+        let address = to_ethers_address(address);
+        let block = Some(self.block_number.into());
+        // DatabaseRef 的接口是同步的，但 ethers 的查询是异步的。
+        // simulate_tx 总是从 spawn_blocking 里调用进来，已经是一个独立的阻塞线程，
+        // 所以这里直接 block_on 就行——block_in_place 是给 tokio 调度的异步 worker 线程用的，
+        // 在 spawn_blocking 线程里调用反而会 panic。三个查询互相独立，用 try_join! 并发打出去。
+        let (balance, nonce, code) = Handle::current().block_on(async {
+            tokio::try_join!(
+                self.provider.get_balance(address, block),
+                self.provider.get_transaction_count(address, block),
+                self.provider.get_code(address, block),
+            )
+        })?;
+        let bytecode = Bytecode::new_raw(code.0.into());
+        Ok(Some(AccountInfo {
+            balance: RevmU256::from_limbs(balance.0),
+            nonce: nonce.as_u64(),
+            code_hash: bytecode.hash_slow(),
+            code: Some(bytecode),
+        }))
+    }
+
+    // 合约字节码按 code_hash 查询；basic_ref 已经把 code 附带在 AccountInfo 里了，
+    // revm 只有在 AccountInfo.code 为 None 时才会回落到这里，正常路径用不到
+    fn code_by_hash_ref(&self, _code_hash: B256) -> Result<Bytecode, Self::Error> {
+        Ok(Bytecode::default())
+    }
+
+    fn storage_ref(&self, address: RevmAddress, index: RevmU256) -> Result<RevmU256, Self::Error> {
+        let address = to_ethers_address(address);
+        let slot = H256::from(index.to_be_bytes());
+        let block = Some(self.block_number.into());
+        // 同上：已经在 spawn_blocking 线程里了，直接 block_on，不用 block_in_place
+        let value = Handle::current().block_on(self.provider.get_storage_at(address, slot, block))?;
+        Ok(RevmU256::from_be_bytes(value.0))
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        let block = Handle::current().block_on(self.provider.get_block(number))?;
+        let hash = block.and_then(|b| b.hash).unwrap_or_default();
+        Ok(B256::from(hash.0))
+    }
+}
+
+// 从最新收到的 NewBlock 构造 revm 的 BlockEnv
+fn apply_block_env(evm_block: &mut revm::primitives::BlockEnv, block: &NewBlock) {
+    evm_block.number = RevmU256::from(block.number.as_u64());
+    evm_block.timestamp = RevmU256::from_limbs(block.timestamp.0);
+    evm_block.basefee = RevmU256::from_limbs(block.base_fee_per_gas.0);
+    evm_block.gas_limit = RevmU256::from_limbs(block.gas_limit.0);
+}
+
+// 从 mempool 里拿到的 pending Transaction 构造 revm 的 TxEnv
+fn apply_tx_env(evm_tx: &mut revm::primitives::TxEnv, tx: &Transaction) {
+    evm_tx.caller = to_revm_address(tx.from);
+    evm_tx.transact_to = match tx.to {
+        Some(to) => TransactTo::Call(to_revm_address(to)),
+        None => TransactTo::create(),
+    };
+    evm_tx.value = RevmU256::from_limbs(tx.value.0);
+    evm_tx.data = tx.input.0.clone().into();
+    evm_tx.gas_limit = tx.gas.as_u64();
+    // EIP-1559 交易没有 gas_price，revm 的 TxEnv::gas_price 在 1559 场景下对应的是
+    // max_fee_per_gas（出价上限），不是 max_priority_fee；直接用 tx.gas_price.unwrap_or_default()
+    // 会把 1559 交易的出价上限当成 0，basefee > 0 时 revm 会直接拒绝执行
+    // （GasPriceLessThanBasefee），这笔交易就被静默吞掉了
+    let gas_price = tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default();
+    evm_tx.gas_price = RevmU256::from_limbs(gas_price.0);
+    evm_tx.gas_priority_fee = tx
+        .max_priority_fee_per_gas
+        .map(|v| RevmU256::from_limbs(v.0));
+    evm_tx.nonce = Some(tx.nonce.as_u64());
+}
+
+/// 在一个共享的 fork 快照上本地模拟执行一笔 pending 交易，
+/// 返回交易执行前后发生变化的账户/存储槽，形状和 trace_call 的 state diff 一致。
+/// `fork_db` 只持有 provider + 区块号，clone 的代价很低，
+/// 可以把同一个 fork 分发给多笔交易并行模拟，而不用等节点一笔笔 trace_call。
+pub fn simulate_tx(fork_db: ForkDb, block: &NewBlock, tx: &Transaction) -> Result<StateDiffMap> {
+    // CacheDB 包一层：同一笔交易模拟内多次读到同一个槽位/账户时不用重复打网络请求
+    let db = CacheDB::new(fork_db);
+    let mut evm = Evm::builder()
+        .with_db(db)
+        .modify_block_env(|b| apply_block_env(b, block))
+        .modify_tx_env(|t| apply_tx_env(t, tx))
+        // mempool 放出来的 pending 交易可能排在同一个发送者更早、还没上链的交易后面，
+        // 这笔交易的 nonce 本来就会比链上账户的 nonce 高；revm 默认的校验会拿
+        // tx.nonce 去对链上账户做 NonceTooHigh 检查，balance/base_fee 同理也是按
+        // 链上当前状态校验的。这里只是投机性地探测"如果这笔交易执行会改动哪些状态"，
+        // 不是真的要把交易打包上链，所以把这几项校验都关掉，否则前面排队的交易
+        // 一笔没模拟完，后面所有交易都会被当成非法交易直接拒绝
+        .modify_cfg_env(|c| {
+            c.disable_nonce_check = true;
+            c.disable_base_fee = true;
+            c.disable_balance_check = true;
+        })
+        .build();
+
+    let ResultAndState { result, state } = evm.transact()?;
+    if let ExecutionResult::Halt { reason, .. } = &result {
+        // 模拟失败（比如 gas 不够、revert 之类）不当成错误，交易本来就可能落不了块
+        log::debug!("simulation halted for tx {}: {:?}", tx.hash, reason);
+    }
+
+    let mut diff = StateDiffMap::new();
+    for (address, account) in state {
+        if !account.is_touched() {
+            continue;
+        }
+        let mut storage = HashMap::new();
+        for (slot, value) in account.storage {
+            if value.is_changed() {
+                storage.insert(
+                    to_ethers_h256(slot),
+                    StorageSlotDiff {
+                        from: to_ethers_h256(value.original_value),
+                        to: to_ethers_h256(value.present_value),
+                    },
+                );
+            }
+        }
+        if !storage.is_empty() {
+            diff.insert(to_ethers_address(address), AccountDiff { storage });
+        }
+    }
+    Ok(diff)
+}
@@ -0,0 +1,82 @@
+use dashmap::DashMap;
+use ethers::{abi, types::Address, types::U256, utils::keccak256};
+use std::collections::HashMap;
+
+use crate::simulation::StateDiffMap;
+
+// WETH 把 balanceOf 映射放在 slot 3，但这只是它自己的布局，不是标准，
+// 没配过覆盖值、也还没探测出来的 token，就在 0..DEFAULT_PROBE_LIMIT 这个范围里挨个试
+const DEFAULT_PROBE_LIMIT: u64 = 10;
+
+fn balance_slot(holder: Address, slot_index: U256) -> ethers::types::H256 {
+    ethers::types::H256::from(keccak256(abi::encode(&[
+        abi::Token::Address(holder),
+        abi::Token::Uint(slot_index),
+    ])))
+}
+
+/// 不同 ERC20 合约的 `balances` 映射可能落在不同的 storage slot 上（hardcode 成 slot 3
+/// 只对 WETH 这类巧合对得上的代币有效）。这里按 token 地址解析该用哪个 slot：
+/// - 先查手工配置的覆盖表（已知合约就不用猜）
+/// - 再查之前探测并缓存过的结果
+/// - 都没有就在 0..probe_limit 范围内挨个算 keccak256(holder, i)，看哪个 slot 真的
+///   出现在这笔交易的 state diff 里，第一个命中的就采用，并缓存下来供下次直接用
+pub struct BalanceSlotResolver {
+    overrides: HashMap<Address, U256>,
+    resolved: DashMap<Address, U256>,
+    probe_limit: u64,
+}
+
+impl Default for BalanceSlotResolver {
+    fn default() -> Self {
+        Self::new(HashMap::new())
+    }
+}
+
+impl BalanceSlotResolver {
+    pub fn new(overrides: HashMap<Address, U256>) -> Self {
+        Self {
+            overrides,
+            resolved: DashMap::new(),
+            probe_limit: DEFAULT_PROBE_LIMIT,
+        }
+    }
+
+    /// 给定 token 和持有者（这里是池子地址），在这笔交易的 state diff 里找出持有者在
+    /// 该 token 下的余额变化；找不到任何命中的 slot 就返回 None
+    pub fn resolve(
+        &self,
+        token: Address,
+        holder: Address,
+        state_diff: &StateDiffMap,
+    ) -> Option<(U256, U256)> {
+        let token_storage = &state_diff.get(&token)?.storage;
+
+        let known_slot = self
+            .overrides
+            .get(&token)
+            .copied()
+            .or_else(|| self.resolved.get(&token).map(|r| *r.value()));
+
+        if let Some(slot_index) = known_slot {
+            let slot = balance_slot(holder, slot_index);
+            return token_storage
+                .get(&slot)
+                .map(|diff| (as_u256(diff.from), as_u256(diff.to)));
+        }
+
+        for raw_index in 0..self.probe_limit {
+            let slot_index = U256::from(raw_index);
+            let slot = balance_slot(holder, slot_index);
+            if let Some(diff) = token_storage.get(&slot) {
+                self.resolved.insert(token, slot_index);
+                return Some((as_u256(diff.from), as_u256(diff.to)));
+            }
+        }
+        None
+    }
+}
+
+fn as_u256(value: ethers::types::H256) -> U256 {
+    U256::from(value.to_fixed_bytes())
+}
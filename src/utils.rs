@@ -1,40 +1,96 @@
-use ethers::types::U256;
-use rand::Rng;
+use ethers::types::{Transaction, U256};
 
-// 计算下个区块基础费用
+use crate::watching::NewBlock;
+
+/// 按交易类型算出"有效 gas 单价"，只有算出来的有效单价才能反映矿工/提议者实际能拿到多少小费，
+/// 不同的交易类型出价方式不一样：
+/// - EIP-1559（dynamic-fee）交易：有 max_fee_per_gas/max_priority_fee_per_gas，
+///   实际单价是 min(max_fee_per_gas, base_fee + max_priority_fee_per_gas)，
+///   并且只有 max_fee_per_gas >= base_fee 才可能被打包
+/// - legacy / access-list 交易：没有 max_fee_per_gas（是 None），直接用固定的 gas_price 出价
+///
+/// 旧逻辑 `tx.max_fee_per_gas.unwrap_or_default() > next_base_fee` 会把 legacy 交易的
+/// max_fee_per_gas（None -> 0）当成出价为 0，直接丢弃所有 legacy 交易，这里按类型分开处理修正这个问题。
+/// 返回 None 表示这笔交易在下一个区块大概率打包不进去。
+pub fn effective_gas_price(tx: &Transaction, base_fee: U256) -> Option<U256> {
+    match tx.max_fee_per_gas {
+        Some(max_fee_per_gas) => {
+            if max_fee_per_gas < base_fee {
+                return None;
+            }
+            let priority_fee = tx.max_priority_fee_per_gas.unwrap_or_default();
+            Some(std::cmp::min(max_fee_per_gas, base_fee + priority_fee))
+        }
+        None => {
+            // legacy/access-list 交易的 gas_price 是固定出价，同样要够得上 base_fee
+            // 才可能被打包，否则会一直卡在 mempool 里
+            let gas_price = tx.gas_price?;
+            if gas_price < base_fee {
+                None
+            } else {
+                Some(gas_price)
+            }
+        }
+    }
+}
+
+// 按照黄皮书 EIP-1559 的公式计算下个区块的基础费用
+// 注意：不能再往结果上加随机数，base fee 是确定性的，加了随机 seed 会让后面所有基于它的判断都不准
 pub fn calculate_next_block_base_fee(
     gas_used: U256,
     gas_limit: U256,
     base_fee_per_gas: U256,
 ) -> U256 {
-    let gas_used = gas_used;
-    // 确定目标燃料使用量 目标是区块燃料限制（gas limit）的 50% 这是以太坊想要维持的理想区块使用率
-    let mut target_gas_used = gas_limit / 2;
-    target_gas_used = if target_gas_used == U256::zero() {
-        U256::one()
-    } else {
-        target_gas_used
-    };
-    // 比较实际使用量和目标使用量
-    let new_base_fee = {
-        // 如果实际使用量 > 目标使用量（区块拥挤）
-        if gas_used > target_gas_used {
-            // 增加基础费用
-            // 增加量 = 当前基础费用 * (实际使用量 - 目标使用量) / 目标使用量 / 8
-            base_fee_per_gas
-                + base_fee_per_gas * (gas_used - target_gas_used)
-                    / target_gas_used
-                    / U256::from(8u64)
+    // 确定目标燃料使用量 目标是区块燃料限制（gas limit）的 50%（ELASTICITY_MULTIPLIER）这是以太坊想要维持的理想区块使用率
+    let target_gas_used = {
+        let target = gas_limit / 2;
+        if target == U256::zero() {
+            U256::one()
         } else {
-            // 如果实际使用量 < 目标使用量（区块未充分使用）
-            // 减少基础费用
-            // 减少量 = 当前基础费用 * (目标使用量 - 实际使用量) / 目标使用量 / 8
-            base_fee_per_gas
-                - base_fee_per_gas * (gas_used - target_gas_used)
-                    / target_gas_used
-                    / U256::from(8u64)
+            target
         }
     };
-    let seed = rand::thread_rng().gen_range(0..9);
-    new_base_fee + U256::from(seed)
+    // 比较实际使用量和目标使用量
+    if gas_used == target_gas_used {
+        // 正好等于目标使用量，基础费用不变
+        base_fee_per_gas
+    } else if gas_used > target_gas_used {
+        // 如果实际使用量 > 目标使用量（区块拥挤），增加基础费用
+        // 增加量 = max(当前基础费用 * (实际使用量 - 目标使用量) / 目标使用量 / 8, 1)，保证至少涨 1 wei
+        let delta = std::cmp::max(
+            base_fee_per_gas * (gas_used - target_gas_used) / target_gas_used / U256::from(8u64),
+            U256::one(),
+        );
+        base_fee_per_gas + delta
+    } else {
+        // 如果实际使用量 < 目标使用量（区块未充分使用），减少基础费用
+        // 减少量 = 当前基础费用 * (目标使用量 - 实际使用量) / 目标使用量 / 8
+        let delta =
+            base_fee_per_gas * (target_gas_used - gas_used) / target_gas_used / U256::from(8u64);
+        // saturating_sub：基础费用永远不会低于 0
+        base_fee_per_gas.saturating_sub(delta)
+    }
+}
+
+/// 把基础费用的递推公式向后滚动 `blocks_ahead` 个区块，预测每个区块的基础费用。
+/// `assumed_fill_ratio` 是假设后续每个区块相对 gas_limit 的使用率（比如 0.6 表示假设每个区块用到 60% 的 gas），
+/// 因为还没发生的区块我们并不知道真实的 gas_used，只能假设一个填充率去外推。
+/// 用来判断一笔 pending 交易即便赶不上下一个区块，是否有可能在接下来几个区块内落地。
+pub fn project_base_fee(
+    new_block: &NewBlock,
+    blocks_ahead: u64,
+    assumed_fill_ratio: f64,
+) -> Vec<U256> {
+    let assumed_gas_used = {
+        // f64 -> U256 没有现成的转换，借助 u128 搭桥；fill_ratio 理应在 [0, 2] 之间，够用了
+        let scaled = (assumed_fill_ratio * 1_000_000.0).round().max(0.0) as u128;
+        new_block.gas_limit * U256::from(scaled) / U256::from(1_000_000u64)
+    };
+    let mut projected = Vec::with_capacity(blocks_ahead as usize);
+    let mut base_fee = new_block.base_fee_per_gas;
+    for _ in 0..blocks_ahead {
+        base_fee = calculate_next_block_base_fee(assumed_gas_used, new_block.gas_limit, base_fee);
+        projected.push(base_fee);
+    }
+    projected
 }
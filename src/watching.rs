@@ -1,16 +1,15 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use cfmms::{
     checkpoint::sync_pools_from_checkpoint,
     dex::{Dex, DexVariant},
     pool::Pool,
     sync::sync_pairs,
 };
+use chrono::Utc;
 use dashmap::DashMap;
 use ethers::{
-    abi,
     providers::{Middleware, Provider, Ws},
-    types::{Address, BlockNumber, Diff, TraceType, Transaction, H160, H256, U256, U64},
-    utils::keccak256,
+    types::{Address, Transaction, H160, H256, U256, U64},
 };
 use log::info;
 use std::{path::Path, str::FromStr, sync::Arc};
@@ -21,7 +20,28 @@ use tokio::{
 // tokio_stream::StreamExt 提供了更完整的流处理功能  ethers::providers::StreamExt 是 ethers 特定的流扩展
 use tokio_stream::StreamExt;
 
-use crate::utils::calculate_next_block_base_fee;
+use crate::events::{EventSink, HttpJsonSink, HttpJsonSinkConfig, StdoutSink, SwapRecord};
+use crate::mempool::Mempool;
+use crate::simulation::{simulate_tx, ForkDb, StateDiffMap};
+use crate::slots::BalanceSlotResolver;
+use crate::utils::{calculate_next_block_base_fee, effective_gas_price, project_base_fee};
+
+// 不止判断下一个区块：把基础费用往后滚动几个区块再看落地概率，一笔出价不够上下一个块
+// 的交易，完全可能在接下来几个区块内基础费用回落之后落地
+const LANDING_WINDOW_BLOCKS: u64 = 3;
+// 假设后续区块的使用率，直接沿用刚看到的这个区块的真实使用率最贴近当下的网络状况；
+// 只有 gas_limit 为 0（理论上不会发生）时才退回这个兜底值；这时 project_base_fee
+// 算出来的假设使用量不管比例是多少都是 0，目标使用量被兜底成 1，
+// 所以投影出来的几个区块基础费用总是往下走
+const LANDING_WINDOW_FALLBACK_FILL_RATIO: f64 = 0.4;
+
+// 用刚收到的这个区块自己的使用率去外推后面几个区块，比固定假设一个比例更贴近实际网络状况
+fn assumed_fill_ratio(new_block: &NewBlock) -> f64 {
+    if new_block.gas_limit.is_zero() {
+        return LANDING_WINDOW_FALLBACK_FILL_RATIO;
+    }
+    new_block.gas_used.as_u128() as f64 / new_block.gas_limit.as_u128() as f64
+}
 // #[derive(Default, Debug, Clone)] 是 Rust 的属性宏，用于自动实现特定的 trait。
 // 实现 Debug trait，允许使用 {:?} 格式化打印
 // 实现 Clone trait，允许创建值的深拷贝
@@ -45,36 +65,79 @@ pub enum Event {
 //     nonce_changes: Map<Address, NonceChange>,       // nonce变化
 //     // ... 其他状态变化
 // }
-// subscribe_pending_txs  注意这里是拿到的pending的交易 然后使用trace_call直接去模拟执行这些pending的交易 查看状态的改变
+// subscribe_pending_txs  注意这里是拿到的pending的交易 然后在本地用revm模拟执行这些pending的交易 查看状态的改变
+// 不再依赖 provider.trace_call(.., TraceType::StateDiff, ..)：
+// 大多数公共 RPC（Infura/Alchemy 免费档）都不开放 trace 接口，而且每笔交易都要一次额外的网络往返。
+// 这里改成基于 revm 的本地模拟：fork_db 只是 provider + 区块号，clone 代价很低，
+// 多笔 pending 交易可以在同一个 fork 快照上并行模拟。
+// UniswapV3 池子自己的 storage 里：slot 0 是打包进一个 slot 的 `slot0`（低 160 位是
+// sqrtPriceX96，紧跟着 24 位是 tick），slot 4 是 `liquidity`（uint128）。
+// 这两个布局是 Uniswap V3 核心合约固定的，不需要和 ERC20 余额一样去猜/探测。
+const UNISWAP_V3_SLOT0_INDEX: u64 = 0;
+const UNISWAP_V3_LIQUIDITY_INDEX: u64 = 4;
+
+fn decode_uniswap_v3_slot0(value: H256) -> (U256, i32) {
+    let raw = U256::from_big_endian(value.as_bytes());
+    let sqrt_price_mask = (U256::one() << 160) - U256::one();
+    let sqrt_price_x96 = raw & sqrt_price_mask;
+    let tick_raw = ((raw >> 160) & U256::from(0xFFFFFFu64)).as_u32();
+    // tick 是 24 位有符号数，最高位是符号位，手动做符号扩展才能还原成 i32
+    let tick = if tick_raw & 0x0080_0000 != 0 {
+        (tick_raw | 0xFF00_0000) as i32
+    } else {
+        tick_raw as i32
+    };
+    (sqrt_price_x96, tick)
+}
+
+/// 如果这是个 UniswapV3 池子，且这笔交易动到了它自己的 slot0/liquidity，
+/// 就把 sqrtPriceX96/tick/liquidity 读出来，拼进 SwapRecord 里，
+/// 这样下游不用再额外发一次 eth_call 就能大致估算成交价格
+fn read_uniswap_v3_pool_state(
+    pool: &Pool,
+    pool_address: Address,
+    state_diff: &StateDiffMap,
+) -> (Option<U256>, Option<i32>, Option<U256>) {
+    if !matches!(pool, Pool::UniswapV3(_)) {
+        return (None, None, None);
+    }
+    let Some(pool_storage) = state_diff.get(&pool_address).map(|diff| &diff.storage) else {
+        return (None, None, None);
+    };
+    let slot0_slot = H256::from_low_u64_be(UNISWAP_V3_SLOT0_INDEX);
+    let liquidity_slot = H256::from_low_u64_be(UNISWAP_V3_LIQUIDITY_INDEX);
+
+    let (sqrt_price_x96, tick) = pool_storage
+        .get(&slot0_slot)
+        .map(|diff| decode_uniswap_v3_slot0(diff.to))
+        .map_or((None, None), |(price, tick)| (Some(price), Some(tick)));
+    let liquidity = pool_storage
+        .get(&liquidity_slot)
+        .map(|diff| U256::from_big_endian(diff.to.as_bytes()));
+
+    (sqrt_price_x96, tick, liquidity)
+}
+
 async fn trace_state_diff(
     provider: Arc<Provider<Ws>>,
     tx: &Transaction,
-    block_number: U64,
+    block: &NewBlock,
     pools: &DashMap<H160, Pool>,
     target_address: Address,
+    resolver: &BalanceSlotResolver,
+    sink: &Arc<dyn EventSink>,
 ) -> Result<()> {
     info!(
         "Tx #{} received. Checking if it touches: {}",
         tx.hash, target_address
     );
-    // trace_call 是以太坊的调试/跟踪功能，用于模拟执行交易并获取详细信息
-    // 模拟执行交易，但不实际改变链上状态 可以获取执行过程中的所有状态变化 可以看到存储变化、余额变化等 对于调试和监控很有用
-    // BTreeMap 的特点：
-    // 1. 有序的 - 按键（地址）排序
-    // 2. 基于 B 树实现
-    // 3. 内存占用可能比 HashMap 小
-    // 4. 适合需要按顺序访问的场景
-    let state_diff = provider
-        .trace_call(
-            tx,                                    // 要模拟执行的交易
-            vec![TraceType::StateDiff],            // 指定要跟踪的类型：状态变化
-            Some(BlockNumber::from(block_number)), // 在哪个区块执行跟踪
-        )
-        .await?
-        // state_diff 包含交易执行前后的状态差异
-        .state_diff
-        .ok_or(anyhow!("state diff does not exist"))?
-        .0;
+    let fork_db = ForkDb::new(provider, block.number);
+    let tx = tx.clone();
+    let block_for_sim = block.clone();
+    // simulate_tx 里面用 Handle::block_on 桥接同步/异步，会阻塞当前线程，
+    // 放到 spawn_blocking 里跑，不占用 tokio 的异步调度
+    let state_diff =
+        tokio::task::spawn_blocking(move || simulate_tx(fork_db, &block_for_sim, &tx)).await??;
     let touched_pools: Vec<Pool> = state_diff
         .keys()
         // pools.get(addr) 从 DashMap 中查找地址对应的池子
@@ -107,42 +170,103 @@ async fn trace_state_diff(
     if touched_pools.is_empty() {
         return Ok(());
     }
-    // 获取目标代币地址的状态变化
-    let target_storage = &state_diff
-        .get(&target_address)
-        .ok_or(anyhow!("no target storage"))?
-        .storage; // 获取存储变化信息
     // 对每个受影响的池子进行检查
     for pool in &touched_pools {
-        // 计算存储槽
-        // keccak256(abi::encode(...)) 是计算存储位置的标准方式
-        // 在 ERC20 合约中，通常使用这种方式存储余额映射
-        let slot = H256::from(keccak256(abi::encode(&[
-            abi::Token::Address(pool.address()),
-            abi::Token::Uint(U256::from(3)),
-        ])));
-       // 将存储值转换为数字
-        if let Some(Diff::Changed(c)) = target_storage.get(&slot) {
-            let from = U256::from(c.from.to_fixed_bytes());
-            let to = U256::from(c.to.to_fixed_bytes());
+        // 目标代币在这个池子里的余额 slot 因合约而异，resolver 负责配置/探测出具体是哪个 slot，
+        // 不再只认 slot 3（那只是 WETH 这类代币碰巧的布局）
+        let Some((from, to)) = resolver.resolve(target_address, pool.address(), &state_diff)
+        else {
+            continue;
+        };
+        if from == to {
+            continue;
+        }
+        let other_token = match pool {
+            Pool::UniswapV2(p) => {
+                if p.token_a == target_address {
+                    p.token_b
+                } else {
+                    p.token_a
+                }
+            }
+            Pool::UniswapV3(p) => {
+                if p.token_a == target_address {
+                    p.token_b
+                } else {
+                    p.token_a
+                }
+            }
+        };
+        // to > from：池子里目标代币余额增加，说明有人拿目标代币换了 other_token
+        // to < from：反过来，池子把目标代币付出去了，说明有人拿 other_token 换了目标代币
+        let (token_in, token_out) = if to > from {
+            (target_address, other_token)
+        } else {
+            (other_token, target_address)
+        };
+        let (sqrt_price_x96, tick, liquidity) =
+            read_uniswap_v3_pool_state(pool, pool.address(), &state_diff);
+        sink.emit(SwapRecord {
+            tx_hash: tx.hash,
+            block_number: block.number,
+            pool_address: pool.address(),
+            token_in,
+            token_out,
+            balance_from: from,
+            balance_to: to,
+            detected_at: Utc::now(),
+            sqrt_price_x96,
+            tick,
+            liquidity,
+        })
+        .await;
+    }
+    Ok(())
+}
 
-            if to > from {
-                // 如果余额增加，说明这个池子收到了目标代币
-                // 这通常意味着有人用目标代币换取了其他代币
-                // if to > from, the balance of pool's <target_token> has increased
-                // thus, the transaction was a call to swap: <target_token> -> token
-                info!(
-                    "(Tx #{}) Balance change: {} -> {} @ Pool {}",
-                    tx.hash,
-                    from,
-                    to,
-                    pool.address()
-                );
+// 按“有效单价是否够格在接下来几个区块内落地”过滤后，把 mempool 新放出来的可执行交易逐个丢去模拟，
+// 而不是只看紧邻的下一个区块——基础费用本来就会随区块使用率涨跌
+async fn simulate_ready_txs(
+    provider: &Arc<Provider<Ws>>,
+    pools: &DashMap<Address, Pool>,
+    target_address: Address,
+    new_block: &NewBlock,
+    ready_txs: Vec<Transaction>,
+    resolver: &BalanceSlotResolver,
+    sink: &Arc<dyn EventSink>,
+) {
+    if ready_txs.is_empty() {
+        return;
+    }
+    let projected_base_fees =
+        project_base_fee(new_block, LANDING_WINDOW_BLOCKS, assumed_fill_ratio(new_block));
+    for tx in ready_txs {
+        // 只要接下来这几个区块里有任意一个基础费用够得上，就认为这笔交易大概率能落地
+        let can_land = projected_base_fees
+            .iter()
+            .any(|base_fee| effective_gas_price(&tx, *base_fee).is_some());
+        if !can_land {
+            continue;
+        }
+        match trace_state_diff(
+            provider.clone(),
+            &tx,
+            new_block,
+            pools,
+            target_address,
+            resolver,
+            sink,
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => {
+                log::warn!("failed to simulate tx {}: {}", tx.hash, err);
             }
         }
     }
-    Ok(())
 }
+
 pub async fn watching_pool(target_address: Address) -> Result<()> {
     let ws_url = std::env::var("WSS_URL").unwrap();
     let provider = Provider::<Ws>::connect(ws_url).await?;
@@ -189,6 +313,12 @@ pub async fn watching_pool(target_address: Address) -> Result<()> {
         pools.insert(pool.address(), pool);
     }
     info!("Uniswap V3 pools synced: {}", pools.len());
+    // 检测到的 SwapRecord 往哪儿送：配了 SINK_HTTP_ENDPOINT 就走 HTTP/ndjson 批量上报，
+    // 否则退回到改造前的行为——直接 info! 到标准输出
+    let sink: Arc<dyn EventSink> = match HttpJsonSinkConfig::from_env() {
+        Some(config) => HttpJsonSink::new(config),
+        None => Arc::new(StdoutSink),
+    };
     // stream data
     // 创建一个广播通道，缓冲区大小为512 // sender: 可以向多个接收者发送消息 // receiver: 接收消息
     let (event_sender, _receiver): (Sender<Event>, _) = broadcast::channel(512);
@@ -267,6 +397,10 @@ pub async fn watching_pool(target_address: Address) -> Result<()> {
     {
         // 订阅事件发送器，创建一个新的接收器
         let mut event_receiver = event_sender.subscribe();
+        // 维护 pending/queued 两个 nonce 分桶，去重/排序以后再往下游放行
+        let mempool = Mempool::default();
+        let resolver = BalanceSlotResolver::default();
+        let sink = sink.clone();
         // 创建一个新的异步任务
         set.spawn(async move {
             // 创建一个默认的新区块状态跟踪器
@@ -287,40 +421,60 @@ pub async fn watching_pool(target_address: Address) -> Result<()> {
                             // block 现在是 NewBlock 类
                             new_block = block;
                             info!("{:?}", new_block);
+                            // 新区块上链了，按每个还在跟踪的账户的链上最新 nonce
+                            // 清掉已经确认过的交易；如果链上 nonce 正好跳过了我们记录的空洞，
+                            // 顺带把 queued 里接得上的交易促进出来
+                            for sender in mempool.tracked_senders() {
+                                if let Ok(on_chain_nonce) =
+                                    provider.get_transaction_count(sender, None).await
+                                {
+                                    let promoted =
+                                        mempool.prune_confirmed(sender, on_chain_nonce.as_u64());
+                                    simulate_ready_txs(
+                                        &provider,
+                                        &pools,
+                                        target_address,
+                                        &new_block,
+                                        promoted,
+                                        &resolver,
+                                        &sink,
+                                    )
+                                    .await;
+                                }
+                            }
                         }
                         // 处理交易事件
                         // 解构 Transaction 变体，提取其中的 Transaction 数据到 tx 变量
                         Event::Transaction(tx) => {
                             // 确保已经有了区块信息  // tx 现在是 Transaction 类型
                             if new_block.number != U64::zero() {
+                                // 第一次见到这个地址，用链上 nonce 给它的确认边界兜底
+                                if !mempool.is_known_account(tx.from) {
+                                    if let Ok(on_chain_nonce) =
+                                        provider.get_transaction_count(tx.from, None).await
+                                    {
+                                        mempool.seed_account(tx.from, on_chain_nonce.as_u64());
+                                    }
+                                }
                                 // 计算下一个区块的基础费用
                                 let next_base_fee = calculate_next_block_base_fee(
                                     new_block.gas_used,
                                     new_block.gas_limit,
                                     new_block.base_fee_per_gas,
                                 );
-                                // 检查交易的最大费用是否高于下一个区块的基础费用
-                                // - EIP-1559 定价机制
-                                //   - 基础费用是动态的
-                                //   - 矿工倾向于选择高于基础费用的交易
-                                //   - 低于基础费用的交易难以被打包
-                                if tx.max_fee_per_gas.unwrap_or_default()
-                                    > U256::from(next_base_fee)
-                                {
-                                    // 如果条件满足，追踪状态变化
-                                    match trace_state_diff(
-                                        provider.clone(),
-                                        &tx,
-                                        new_block.number,
-                                        &pools,
-                                        target_address.clone(),
-                                    )
-                                    .await
-                                    {
-                                        Ok(_) => {}
-                                        Err(_) => {}
-                                    }
-                                }
+                                // 插进 mempool：同一个 (sender, nonce) 出价不够高的替换会被挡掉，
+                                // 只有新晋可执行（nonce 和账户连续）的交易才会被放出来
+                                let ready = mempool.insert(tx, next_base_fee);
+                                simulate_ready_txs(
+                                    &provider,
+                                    &pools,
+                                    target_address,
+                                    &new_block,
+                                    ready,
+                                    &resolver,
+                                    &sink,
+                                )
+                                .await;
                             }
                         }
                     },